@@ -3,8 +3,10 @@
 use super::{Result, Error};
 
 use std::io::{self, Read, Write};
-use std::sync::mpsc;
-use std::net::UdpSocket;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::net::{UdpSocket, SocketAddr};
 
 use websocket::ws::sender::Sender as SenderTrait;
 use websocket::client::{Client, Sender, Receiver};
@@ -14,7 +16,7 @@ use websocket::message::{Message as WsMessage, Type as MessageType};
 use serde_json;
 use serde_json::builder::ObjectBuilder;
 
-use byteorder::{LittleEndian, BigEndian, WriteBytesExt, ReadBytesExt};
+use byteorder::{LittleEndian, BigEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
 
 use super::model::*;
 
@@ -24,6 +26,21 @@ use super::model::*;
 /// format, at 48000Hz.
 pub type AudioSource = Box<Read + Send>;
 
+/// A source of pre-encoded Opus audio, as produced by `encode_opus_frames`.
+///
+/// Each frame is preceded by its length as a little-endian `i16`. Playing
+/// from this kind of source alone, at full volume, skips the per-frame Opus
+/// encoding step, which is useful for sound effects or clips that get
+/// replayed often; when it is mixed with other sources or its volume is
+/// changed, its frames are decoded and re-encoded like any other audio.
+pub type OpusFrameSource = Box<Read + Send>;
+
+/// A callback which receives decoded PCM audio from other users.
+///
+/// Called with the speaking user's id and a buffer of signed 16-bit
+/// little-endian mono PCM samples at 48000Hz.
+pub type VoiceReceiver = Box<FnMut(UserId, &[i16]) + Send>;
+
 /// A websocket connection to the voice servers.
 ///
 /// A VoiceConnection may be active or inactive. Use `voice_connect` and
@@ -34,6 +51,33 @@ pub struct VoiceConnection {
 	session_id: Option<String>,
 	sender: mpsc::Sender<Status>,
 	receiver: Option<mpsc::Receiver<Status>>,
+	status: Option<mpsc::Receiver<ConnectionStatus>>,
+	state: Arc<Mutex<ConnectionState>>,
+}
+
+/// The result of a `VoiceConnection`'s connection attempt, reported back
+/// from the voice thread rather than panicking it.
+pub enum ConnectionStatus {
+	/// The handshake and IP discovery completed and voice data can now be
+	/// sent and received.
+	Connected,
+	/// The connection attempt failed.
+	Failed(Error),
+}
+
+/// The voice connection's current lifecycle state, readable at any time
+/// through `VoiceConnection::connection_state` - similar in spirit to
+/// `is_running`, but with more detail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+	/// No connection has been established, or the last one was closed.
+	Closed,
+	/// The handshake and IP discovery are in progress.
+	Connecting,
+	/// Voice data can be sent and received.
+	Connected,
+	/// The previous connection was lost and a new one is being negotiated.
+	Reconnecting,
 }
 
 impl VoiceConnection {
@@ -45,19 +89,63 @@ impl VoiceConnection {
 			session_id: None,
 			sender: tx,
 			receiver: Some(rx),
+			status: None,
+			state: Arc::new(Mutex::new(ConnectionState::Closed)),
 		}
 	}
 
-	/// Play from the given audio source.
+	/// Play from the given audio source, mixing it with any other sources
+	/// that are currently playing.
 	pub fn play(&self, source: AudioSource) {
-		let _ = self.sender.send(Status::Source(source));
+		self.play_returning(source);
+	}
+
+	/// Play from the given audio source, mixing it with any other sources
+	/// that are currently playing, and return a handle which can be used to
+	/// stop it or adjust its volume independently of the others.
+	pub fn play_returning(&self, source: AudioSource) -> SourceHandle {
+		self.play_format(AudioFormat::Pcm(source))
+	}
+
+	/// Play from a source of already Opus-encoded frames (see
+	/// `OpusFrameSource` and `encode_opus_frames`), skipping the per-frame
+	/// Opus encoding step entirely when this is the only source playing.
+	pub fn play_opus(&self, source: OpusFrameSource) {
+		if let Err(err) = self.play_opus_returning(source) {
+			warn!("play_opus: failed to start Opus source: {:?}", err);
+		}
 	}
 
-	/// Stop the currently playing audio source.
+	/// Like `play_opus`, but returns a handle which can be used to stop the
+	/// source or adjust its volume independently of any others.
+	pub fn play_opus_returning(&self, source: OpusFrameSource) -> Result<SourceHandle> {
+		let decoder = try!(::opus::Decoder::new(48000, ::opus::Channels::Mono));
+		Ok(self.play_format(AudioFormat::Opus(source, decoder)))
+	}
+
+	fn play_format(&self, format: AudioFormat) -> SourceHandle {
+		let volume = Arc::new(Mutex::new(1.0));
+		let stopped = Arc::new(AtomicBool::new(false));
+		let handle = SourceHandle { volume: volume.clone(), stopped: stopped.clone() };
+		let _ = self.sender.send(Status::Source(ActiveSource {
+			format: format,
+			volume: volume,
+			stopped: stopped,
+		}));
+		handle
+	}
+
+	/// Stop all currently playing audio sources.
 	pub fn stop(&self) {
 		let _ = self.sender.send(Status::Stop);
 	}
 
+	/// Set or clear the callback used to receive decoded voice audio from
+	/// other users in the channel.
+	pub fn set_receiver(&self, receiver: Option<VoiceReceiver>) {
+		let _ = self.sender.send(Status::SetReceiver(receiver));
+	}
+
 	/// Update the voice state based on an event.
 	pub fn update(&mut self, event: &Event) {
 		match *event {
@@ -72,7 +160,9 @@ impl VoiceConnection {
 			}
 			Event::VoiceServerUpdate { ref server_id, ref endpoint, ref token } => {
 				if let Some(endpoint) = endpoint.as_ref() {
-					self.connect(server_id, endpoint.clone(), token).expect("Voice::connect failure")
+					if let Err(err) = self.connect(server_id, endpoint.clone(), token) {
+						warn!("Voice::connect failure: {:?}", err);
+					}
 				} else {
 					self.disconnect()
 				}
@@ -89,10 +179,27 @@ impl VoiceConnection {
 		}
 	}
 
+	/// Poll for a change in the status of the current connection attempt,
+	/// such as its success or failure. Returns `None` if there is nothing
+	/// new since the last call.
+	pub fn poll_status(&self) -> Option<ConnectionStatus> {
+		self.status.as_ref().and_then(|rx| rx.try_recv().ok())
+	}
+
+	/// The voice connection's current lifecycle state.
+	pub fn connection_state(&self) -> ConnectionState {
+		*self.state.lock().unwrap()
+	}
+
 	fn disconnect(&mut self) {
+		// ask the voice thread, if any, to send a clean disconnect and stop
+		// reconnecting before we stop listening to it
+		let _ = self.sender.send(Status::Disconnect);
+
 		let (tx, rx) = mpsc::channel();
 		self.sender = tx;
 		self.receiver = Some(rx);
+		self.status = None;
 	}
 
 	fn connect(&mut self, server_id: &ServerId, mut endpoint: String, token: &str) -> Result<()> {
@@ -111,31 +218,27 @@ impl VoiceConnection {
 			let len = endpoint.len();
 			endpoint.truncate(len - 3);
 		}
-		// establish the websocket connection
-		let url = match ::websocket::client::request::Url::parse(&format!("wss://{}", endpoint)) {
-			Ok(url) => url,
-			Err(_) => return Err(Error::Other("Invalid URL in Voice::connect()"))
-		};
-		let response = try!(try!(Client::connect(url)).send());
-		try!(response.validate());
-		let (mut sender, receiver) = response.begin().split();
-
-		// send the handshake
-		let map = ObjectBuilder::new()
-			.insert("op", 0)
-			.insert_object("d", |object| object
-				.insert("server_id", &server_id.0)
-				.insert("user_id", &self.user_id.0)
-				.insert("session_id", self.session_id.as_ref().expect("no session id"))
-				.insert("token", token)
-			)
-			.unwrap();
-		try!(sender.send_message(&WsMessage::text(try!(serde_json::to_string(&map)))));
 
-		// spin up the voice thread, where most of the action will take place
+		let session_id = try!(self.session_id.clone().ok_or(Error::Other("no session id")));
+		let user_id = self.user_id;
+		let server_id = server_id.clone();
+		let token = token.to_owned();
+
+		// spin up the voice thread, where the handshake and all further
+		// action take place; it owns its own reconnection so a dropped
+		// websocket or UDP send doesn't need to be handled here
+		let (status_tx, status_rx) = mpsc::channel();
+		self.status = Some(status_rx);
+		let state = self.state.clone();
 		try!(::std::thread::Builder::new()
 			.name("Discord Voice Thread".into())
-			.spawn(move || voice_thread(endpoint, sender, receiver, rx).unwrap()));
+			.spawn(move || {
+				if let Err(err) = voice_thread(user_id, server_id, session_id, token, endpoint, rx, status_tx.clone(), state.clone()) {
+					warn!("Voice thread error: {:?}", err);
+					*state.lock().unwrap() = ConnectionState::Closed;
+					let _ = status_tx.send(ConnectionStatus::Failed(err));
+				}
+			}));
 		Ok(())
 	}
 }
@@ -190,6 +293,36 @@ pub fn open_ytdl_stream(url: &str) -> Result<AudioSource> {
 	open_ffmpeg_stream(url)
 }
 
+/// Use `ffmpeg` to pre-encode an audio file into the length-prefixed Opus
+/// frame format `play_opus` expects, and write the result to `out`.
+///
+/// This only needs to be done once per file: the encoded output can be
+/// replayed with `play_opus` far more cheaply than re-transcoding the
+/// original file on every playback. Requires `ffmpeg` to be on the path.
+pub fn encode_opus_frames<P: AsRef<::std::ffi::OsStr>, W: Write>(path: P, out: &mut W) -> Result<()> {
+	use opus;
+
+	let mut pcm = try!(open_ffmpeg_stream(path));
+	let mut encoder = try!(opus::Encoder::new(48000, opus::Channels::Mono, opus::CodingMode::Audio));
+	let mut buffer = [0i16; 960];
+	loop {
+		let len = try!(next_frame(&mut pcm, &mut buffer[..]));
+		if len == 0 {
+			break
+		} else if len < buffer.len() {
+			for value in &mut buffer[len..] {
+				*value = 0;
+			}
+		}
+
+		let mut encoded = [0; 256];
+		let n = encoder.encode(&buffer, &mut encoded).expect("failed encode");
+		try!(out.write_i16::<LittleEndian>(n as i16));
+		try!(out.write_all(&encoded[..n]));
+	}
+	Ok(())
+}
+
 /// A stream that reads from a child's stdout and kills it on drop.
 struct ProcessStream(::std::process::Child);
 
@@ -207,9 +340,84 @@ impl Drop for ProcessStream {
 }
 
 enum Status {
-	Source(AudioSource),
+	Source(ActiveSource),
 	Stop,
 	Poke,
+	SetReceiver(Option<VoiceReceiver>),
+	Disconnect,
+}
+
+/// A handle to a source handed to `play_returning`, letting the caller stop
+/// it or adjust its volume independently of any other sources being mixed.
+pub struct SourceHandle {
+	volume: Arc<Mutex<f32>>,
+	stopped: Arc<AtomicBool>,
+}
+
+impl SourceHandle {
+	/// Stop this source; it will be dropped from the mix on the next tick.
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::Relaxed);
+	}
+
+	/// Set this source's volume, where `1.0` leaves it unchanged.
+	pub fn set_volume(&self, volume: f32) {
+		*self.volume.lock().unwrap() = volume;
+	}
+}
+
+/// A source mixed into the voice thread's output, along with the shared
+/// state its `SourceHandle` uses to control it.
+struct ActiveSource {
+	format: AudioFormat,
+	volume: Arc<Mutex<f32>>,
+	stopped: Arc<AtomicBool>,
+}
+
+/// The two kinds of audio an `ActiveSource` can carry.
+enum AudioFormat {
+	/// Raw `pcm_s16le` samples, re-encoded with Opus every frame.
+	Pcm(AudioSource),
+	/// Already Opus-encoded frames, with a decoder kept around in case this
+	/// source ends up needing to be mixed with others.
+	Opus(OpusFrameSource, ::opus::Decoder),
+}
+
+/// The voice packet encryption mode, negotiated from the `modes` list Discord
+/// offers in the Handshake/Ready events. The three modes only differ in how
+/// the 24-byte nonce is derived and where it is transmitted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EncryptionMode {
+	/// The nonce is the 12-byte RTP header, zero-padded. Not transmitted.
+	Normal,
+	/// The nonce is 24 random bytes, appended to the end of the packet.
+	Suffix,
+	/// The nonce is an incrementing `u32` counter, appended to the end of
+	/// the packet as 4 big-endian bytes.
+	Lite,
+}
+
+impl EncryptionMode {
+	/// Pick the best available mode out of those Discord offered.
+	fn negotiate(modes: &[String]) -> Result<Self> {
+		if modes.iter().any(|m| m == "xsalsa20_poly1305_lite") {
+			Ok(EncryptionMode::Lite)
+		} else if modes.iter().any(|m| m == "xsalsa20_poly1305_suffix") {
+			Ok(EncryptionMode::Suffix)
+		} else if modes.iter().any(|m| m == "xsalsa20_poly1305") {
+			Ok(EncryptionMode::Normal)
+		} else {
+			Err(Error::Protocol("No supported voice encryption mode was offered"))
+		}
+	}
+
+	fn as_str(&self) -> &'static str {
+		match *self {
+			EncryptionMode::Normal => "xsalsa20_poly1305",
+			EncryptionMode::Suffix => "xsalsa20_poly1305_suffix",
+			EncryptionMode::Lite => "xsalsa20_poly1305_lite",
+		}
+	}
 }
 
 fn recv_message(receiver: &mut Receiver<WebSocketStream>) -> Result<VoiceEvent> {
@@ -227,24 +435,66 @@ fn recv_message(receiver: &mut Receiver<WebSocketStream>) -> Result<VoiceEvent>
 	})
 }
 
-fn voice_thread(
-	endpoint: String,
-	mut sender: Sender<WebSocketStream>,
-	mut receiver: Receiver<WebSocketStream>,
-	channel: mpsc::Receiver<Status>,
-) -> Result<()> {
-	use opus;
+/// The sockets and negotiated parameters produced by a successful handshake,
+/// ready for `run_session` to start sending and receiving voice data.
+struct Session {
+	sender: Sender<WebSocketStream>,
+	udp: UdpSocket,
+	destination: SocketAddr,
+	ssrc: u32,
+	interval: u64,
+	encryption_mode: EncryptionMode,
+	encryption_key: ::sodiumoxide::crypto::secretbox::Key,
+	incoming_rx: mpsc::Receiver<(UserId, Vec<i16>)>,
+	failure_rx: mpsc::Receiver<()>,
+	/// Shared with `voice_receive_thread`; set on drop so its blocking UDP
+	/// read gives up instead of leaking past this session's lifetime.
+	closed: Arc<AtomicBool>,
+}
+
+impl Drop for Session {
+	fn drop(&mut self) {
+		// wake `voice_receive_thread` out of its blocking read, and shut down
+		// the websocket to interrupt `drain_thread`'s blocking recv - this
+		// runs whenever a session ends, whether cleanly, by error, or by
+		// being replaced on reconnect, so neither thread is ever leaked
+		self.closed.store(true, Ordering::Relaxed);
+		let _ = self.sender.get_mut().shutdown(::std::net::Shutdown::Both);
+	}
+}
+
+/// Connect to the voice websocket, identify, negotiate an encryption mode,
+/// and perform IP discovery over UDP. Called once for the initial connection
+/// and again, with the same credentials, for every reconnect attempt.
+fn establish(user_id: UserId, server_id: &ServerId, session_id: &str, token: &str, endpoint: &str) -> Result<Session> {
 	use sodiumoxide::crypto::secretbox as crypto;
 	use std::io::Cursor;
 
+	// connect to the voice websocket and identify ourselves
+	let url = try!(format!("wss://{}", endpoint).parse()
+		.map_err(|_| Error::Protocol("Invalid voice websocket URL")));
+	let request = try!(Client::connect(url));
+	let response = try!(request.send());
+	try!(response.validate());
+	let (mut sender, mut receiver) = response.begin().split();
+
+	let map = ObjectBuilder::new()
+		.insert("op", 0)
+		.insert_object("d", |object| object
+			.insert("server_id", server_id.0)
+			.insert("user_id", user_id.0)
+			.insert("session_id", session_id)
+			.insert("token", token)
+		)
+		.unwrap();
+	try!(sender.send_message(&WsMessage::text(try!(serde_json::to_string(&map)))));
+
 	// read the first websocket message
 	let (interval, port, ssrc, modes) = match try!(recv_message(&mut receiver)) {
 		VoiceEvent::Handshake { heartbeat_interval, port, ssrc, modes } => (heartbeat_interval, port, ssrc, modes),
 		_ => return Err(Error::Protocol("First voice event was not Handshake"))
 	};
-	if !modes.iter().find(|&s| s == "xsalsa20_poly1305").is_some() {
-		return Err(Error::Protocol("Voice mode \"xsalsa20_poly1305\" unavailable"))
-	}
+	let encryption_mode = try!(EncryptionMode::negotiate(&modes));
 
 	// bind a UDP socket and send the ssrc value in a packet as identification
 	let udp = try!(UdpSocket::bind("0.0.0.0:0"));
@@ -258,12 +508,34 @@ fn voice_thread(
 	};
 	try!(udp.send_to(&bytes, destination));
 
-	// receive the response to the identification to get port and address info
+	// receive the response to the identification to get our external
+	// address and port, as discovered by the voice server; bounded by a
+	// timeout, since a dropped discovery packet (a NAT or firewall silently
+	// swallowing the reply is a common real-world case) would otherwise
+	// block here forever instead of reporting a failed connection attempt
+	try!(udp.set_read_timeout(Some(::std::time::Duration::from_millis(5000))));
 	let mut bytes = [0; 256];
-	let (len, _remote_addr) = try!(udp.recv_from(&mut bytes));
-	let mut cursor = Cursor::new(&bytes[..len]);
-	let _ = try!(cursor.read_u32::<LittleEndian>()); // discard padding
-	let port_number = try!(cursor.read_u16::<LittleEndian>());
+	let (len, _remote_addr) = match udp.recv_from(&mut bytes) {
+		Ok(result) => result,
+		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+			return Err(Error::Protocol("Timed out waiting for IP discovery response"))
+		}
+		Err(e) => return Err(From::from(e)),
+	};
+	try!(udp.set_read_timeout(None));
+	if len < 70 {
+		return Err(Error::Protocol("IP discovery response was too short"))
+	}
+	// bytes[0..4] are padding/type; the external IP is a NUL-terminated
+	// ASCII string starting at offset 4, and the port is the trailing
+	// big-endian u16
+	const IP_START: usize = 4;
+	let ip_end = bytes[IP_START..len - 2].iter()
+		.position(|&b| b == 0)
+		.map(|pos| IP_START + pos)
+		.unwrap_or(len - 2);
+	let external_address = String::from_utf8_lossy(&bytes[IP_START..ip_end]).into_owned();
+	let port_number = BigEndian::read_u16(&bytes[len - 2..len]);
 
 	// send the acknowledgement websocket message
 	let map = ObjectBuilder::new()
@@ -271,9 +543,9 @@ fn voice_thread(
 		.insert_object("d", |object| object
 			.insert("protocol", "udp")
 			.insert_object("data", |object| object
-				.insert("address", "")
+				.insert("address", external_address)
 				.insert("port", port_number)
-				.insert("mode", "xsalsa20_poly1305")
+				.insert("mode", encryption_mode.as_str())
 			)
 		)
 		.unwrap();
@@ -285,8 +557,8 @@ fn voice_thread(
 		match try!(recv_message(&mut receiver)) {
 			VoiceEvent::Ready { mode, secret_key } => {
 				encryption_key = crypto::Key::from_slice(&secret_key).expect("failed to create key");
-				if mode != "xsalsa20_poly1305" {
-					return Err(Error::Protocol("Voice mode in Ready was not \"xsalsa20_poly1305\""))
+				if mode != encryption_mode.as_str() {
+					return Err(Error::Protocol("Voice mode in Ready did not match the negotiated mode"))
 				}
 				break
 			}
@@ -295,41 +567,120 @@ fn voice_thread(
 		}
 	}
 
+	// maps each speaking user's ssrc to their UserId, filled in from the
+	// Speaking (op 5) events that arrive on the websocket
+	let ssrc_map = Arc::new(Mutex::new(HashMap::new()));
+
+	// a failed drain or receive thread means the connection is no longer
+	// usable; either sends here so `run_session` knows to reconnect
+	let (failure_tx, failure_rx) = mpsc::channel();
+
+	// set by `Session`'s `Drop` impl to tear this session's threads down
+	let closed = Arc::new(AtomicBool::new(false));
+
 	// start a drain thread for the websocket receiver - without this, eventually
-	// the OS buffer will fill and the connection will be dropped
+	// the OS buffer will fill and the connection will be dropped. It also keeps
+	// the ssrc map up to date as users start and stop speaking.
+	let drain_ssrc_map = ssrc_map.clone();
+	let drain_failure_tx = failure_tx.clone();
 	try!(::std::thread::Builder::new()
 		.name("Discord Voice Drain Thread".into())
-		.spawn(move || drain_thread(receiver)));
+		.spawn(move || {
+			drain_thread(receiver, drain_ssrc_map);
+			let _ = drain_failure_tx.send(());
+		}));
+
+	// start a receive thread that decodes incoming voice packets from other
+	// users and forwards the PCM samples back to the main loop
+	let (incoming_tx, incoming_rx) = mpsc::channel();
+	let receive_udp = try!(udp.try_clone());
+	let receive_key = encryption_key.clone();
+	let receive_closed = closed.clone();
+	try!(::std::thread::Builder::new()
+		.name("Discord Voice Receive Thread".into())
+		.spawn(move || {
+			voice_receive_thread(receive_udp, receive_key, encryption_mode, ssrc_map, incoming_tx, receive_closed);
+			let _ = failure_tx.send(());
+		}));
+
+	Ok(Session {
+		sender: sender,
+		udp: udp,
+		destination: destination,
+		ssrc: ssrc,
+		interval: interval,
+		encryption_mode: encryption_mode,
+		encryption_key: encryption_key,
+		incoming_rx: incoming_rx,
+		failure_rx: failure_rx,
+		closed: closed,
+	})
+}
+
+/// Send the op 13 disconnect payload and close the websocket cleanly.
+fn send_disconnect(sender: &mut Sender<WebSocketStream>) -> Result<()> {
+	let map = ObjectBuilder::new()
+		.insert("op", 13)
+		.insert("d", serde_json::Value::Null)
+		.unwrap();
+	try!(sender.send_message(&WsMessage::text(try!(serde_json::to_string(&map)))));
+	try!(sender.get_mut().shutdown(::std::net::Shutdown::Both));
+	Ok(())
+}
+
+/// Run the send/receive loop for one established session, carrying forward
+/// the caller's audio sources and RTP state across reconnects. Returns
+/// `Ok(())` if the connection was intentionally closed - either by
+/// `Status::Disconnect` or because the `VoiceConnection` was dropped - and
+/// `Err` if it was lost and should be re-established by the caller.
+fn run_session(
+	mut session: Session,
+	channel: &mpsc::Receiver<Status>,
+	audio: &mut Vec<ActiveSource>,
+	receiver_callback: &mut Option<VoiceReceiver>,
+	sequence: &mut u16,
+	timestamp: &mut u32,
+	lite_nonce: &mut u32,
+) -> Result<()> {
+	use opus;
 
 	// prepare buffers for later use
 	let mut opus = try!(opus::Encoder::new(48000, opus::Channels::Mono, opus::CodingMode::Audio));
 	let mut audio_buffer = [0i16; 960];
 	let mut packet = Vec::with_capacity(256);
-	let mut sequence = 0;
-	let mut timestamp = 0;
 	let mut speaking = false;
 
-	let mut audio = None;
-
 	let audio_duration = ::time::Duration::milliseconds(20);
-	let keepalive_duration = ::time::Duration::milliseconds(interval as i64);
+	let keepalive_duration = ::time::Duration::milliseconds(session.interval as i64);
 	let mut audio_timer = ::Timer::new(audio_duration);
 	let mut keepalive_timer = ::Timer::new(keepalive_duration);
 
-	let mut nonce = crypto::Nonce([0; 24]);
-
-	// start the main loop
-	info!("Voice connected to {}", endpoint);
-	'outer: loop {
+	loop {
 		::sleep_ms(3);
 
+		if session.failure_rx.try_recv().is_ok() {
+			return Err(Error::Protocol("Voice websocket or UDP receive thread ended unexpectedly"))
+		}
+
 		loop {
 			match channel.try_recv() {
-				Ok(Status::Source(source)) => audio = Some(source),
-				Ok(Status::Stop) => audio = None,
+				Ok(Status::Source(source)) => audio.push(source),
+				Ok(Status::Stop) => audio.clear(),
 				Ok(Status::Poke) => {},
+				Ok(Status::SetReceiver(callback)) => *receiver_callback = callback,
+				Ok(Status::Disconnect) => {
+					// an intentional close, even if the disconnect can't be sent
+					let _ = send_disconnect(&mut session.sender);
+					return Ok(())
+				}
 				Err(mpsc::TryRecvError::Empty) => break,
-				Err(mpsc::TryRecvError::Disconnected) => break 'outer,
+				Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+			}
+		}
+
+		while let Ok((user_id, samples)) = session.incoming_rx.try_recv() {
+			if let Some(ref mut callback) = *receiver_callback {
+				callback(user_id, &samples);
 			}
 		}
 
@@ -339,51 +690,190 @@ fn voice_thread(
 				.insert("d", serde_json::Value::Null)
 				.unwrap();
 			let json = try!(serde_json::to_string(&map));
-			try!(sender.send_message(&WsMessage::text(json)));
+			try!(session.sender.send_message(&WsMessage::text(json)));
 		}
 
 		if audio_timer.check_and_add(audio_duration) {
-			// read the audio from the source
-			let len = match audio.as_mut() {
-				Some(source) => try!(next_frame(source, &mut audio_buffer[..])),
-				None => 0
-			};
-			if len == 0 {
-				// stop speaking, don't send any audio
-				try!(set_speaking(&mut sender, &mut speaking, false));
+			// fast path: a single pre-encoded Opus source at full volume can
+			// be forwarded straight into the packet, without decoding or
+			// re-encoding it; anything scaling its volume has to go through
+			// the decode-mix-reencode path below instead
+			let is_lone_opus = audio.len() == 1 && match audio[0].format {
+				AudioFormat::Opus(..) => true,
+				AudioFormat::Pcm(..) => false,
+			} && *audio[0].volume.lock().unwrap() == 1.0;
+			if is_lone_opus {
+				let frame = if audio[0].stopped.load(Ordering::Relaxed) {
+					None
+				} else if let AudioFormat::Opus(ref mut source, _) = audio[0].format {
+					next_opus_frame(source).unwrap_or(None)
+				} else {
+					None
+				};
+				match frame {
+					Some(data) => {
+						try!(set_speaking(&mut session.sender, &mut speaking, true));
+						try!(send_voice_packet(&session.udp, session.destination, &mut packet, *sequence, *timestamp,
+							session.ssrc, session.encryption_mode, &session.encryption_key, lite_nonce, &data));
+						*sequence = sequence.wrapping_add(1);
+						*timestamp = timestamp.wrapping_add(960);
+					}
+					None => {
+						audio.clear();
+						try!(set_speaking(&mut session.sender, &mut speaking, false));
+					}
+				}
 				continue
-			} else if len < audio_buffer.len() {
-				// zero-fill the rest of the buffer
-				for value in &mut audio_buffer[len..] {
-					*value = 0;
+			}
+
+			// general path: pull a frame from each live source (decoding any
+			// Opus ones), scale it by its volume, and sum the results into
+			// the mix; sources that are stopped, have run out, or fail to read
+			// are dropped
+			let mut mix = [0i32; 960];
+			let mut any_active = false;
+			let mut i = 0;
+			while i < audio.len() {
+				if audio[i].stopped.load(Ordering::Relaxed) {
+					audio.remove(i);
+					continue
 				}
+				let frame = match audio[i].format {
+					AudioFormat::Pcm(ref mut source) => {
+						let mut frame = [0i16; 960];
+						let len = next_frame(source, &mut frame[..]).unwrap_or(0);
+						if len == 0 { None } else { Some(frame) }
+					}
+					AudioFormat::Opus(ref mut source, ref mut decoder) => {
+						match next_opus_frame(source).unwrap_or(None) {
+							Some(data) => {
+								let mut frame = [0i16; 960];
+								match decoder.decode(&data, &mut frame, false) {
+									Ok(samples) => {
+										for value in &mut frame[samples..] { *value = 0; }
+										Some(frame)
+									}
+									// a malformed frame from this source shouldn't
+									// take down the whole voice thread - drop it
+									Err(_) => None,
+								}
+							}
+							None => None,
+						}
+					}
+				};
+				let frame = match frame {
+					Some(frame) => frame,
+					None => { audio.remove(i); continue }
+				};
+				any_active = true;
+				let volume = *audio[i].volume.lock().unwrap();
+				for (sum, &sample) in mix.iter_mut().zip(frame.iter()) {
+					*sum += (sample as f32 * volume) as i32;
+				}
+				i += 1;
+			}
+
+			if !any_active {
+				// stop speaking, don't send any audio
+				try!(set_speaking(&mut session.sender, &mut speaking, false));
+				continue
+			}
+			for (dst, &sum) in audio_buffer.iter_mut().zip(mix.iter()) {
+				*dst = sum.max(::std::i16::MIN as i32).min(::std::i16::MAX as i32) as i16;
 			}
-			try!(set_speaking(&mut sender, &mut speaking, true));
-
-			// prepare the packet header
-			const HEADER_LEN: usize = 12;
-			packet.clear();
-			try!(packet.write_all(&[0x80, 0x78]));
-			try!(packet.write_u16::<BigEndian>(sequence));
-			try!(packet.write_u32::<BigEndian>(timestamp));
-			try!(packet.write_u32::<BigEndian>(ssrc));
-			nonce.0[..12].clone_from_slice(&packet[..12]);
-
-			// encode the audio data and transmit it
+			try!(set_speaking(&mut session.sender, &mut speaking, true));
+
+			// encode the mixed audio data and transmit it
 			let mut new_opus_buf = [0; 256];
 			let len = opus.encode(&audio_buffer, &mut new_opus_buf).expect("failed encode");
-			packet.extend(crypto::seal(&new_opus_buf[..len], &nonce, &encryption_key));
-			try!(udp.send_to(&packet[..], destination));
+			try!(send_voice_packet(&session.udp, session.destination, &mut packet, *sequence, *timestamp,
+				session.ssrc, session.encryption_mode, &session.encryption_key, lite_nonce, &new_opus_buf[..len]));
 
-			sequence = sequence.wrapping_add(1);
-			timestamp = timestamp.wrapping_add(960);
+			*sequence = sequence.wrapping_add(1);
+			*timestamp = timestamp.wrapping_add(960);
 		}
 	}
+}
 
-	// shutting down the sender like this will also terminate the drain thread
-	try!(sender.get_mut().shutdown(::std::net::Shutdown::Both));
-	info!("Voice disconnected");
-	Ok(())
+/// Own the voice websocket and UDP socket for the lifetime of a single
+/// `connect()` call: perform the initial handshake, run the send/receive
+/// loop, and transparently reconnect with capped exponential backoff if the
+/// connection is lost, until told to disconnect or dropped. A reconnect
+/// attempt that stalls (e.g. a dropped IP discovery reply) times out inside
+/// `establish()` rather than hanging here, so it's retried with backoff like
+/// any other failed attempt.
+fn voice_thread(
+	user_id: UserId,
+	server_id: ServerId,
+	session_id: String,
+	token: String,
+	endpoint: String,
+	channel: mpsc::Receiver<Status>,
+	status: mpsc::Sender<ConnectionStatus>,
+	state: Arc<Mutex<ConnectionState>>,
+) -> Result<()> {
+	*state.lock().unwrap() = ConnectionState::Connecting;
+	let mut session = try!(establish(user_id, &server_id, &session_id, &token, &endpoint));
+	*state.lock().unwrap() = ConnectionState::Connected;
+	let _ = status.send(ConnectionStatus::Connected);
+	info!("Voice connected to {}", endpoint);
+
+	let mut audio: Vec<ActiveSource> = Vec::new();
+	let mut receiver_callback: Option<VoiceReceiver> = None;
+	let mut sequence = 0;
+	let mut timestamp = 0;
+	// only used by the lite nonce mode, where the nonce is an incrementing counter
+	let mut lite_nonce: u32 = 0;
+	let mut backoff_ms = 1000;
+
+	loop {
+		match run_session(session, &channel, &mut audio, &mut receiver_callback,
+			&mut sequence, &mut timestamp, &mut lite_nonce) {
+			Ok(()) => {
+				*state.lock().unwrap() = ConnectionState::Closed;
+				info!("Voice disconnected");
+				return Ok(())
+			}
+			Err(err) => warn!("Voice connection lost, reconnecting: {:?}", err),
+		}
+
+		*state.lock().unwrap() = ConnectionState::Reconnecting;
+		session = loop {
+			// give up on reconnecting if we're told to disconnect, or if the
+			// VoiceConnection (and its sender) was dropped, rather than
+			// retrying forever with no way to observe either
+			loop {
+				match channel.try_recv() {
+					Ok(Status::Source(source)) => audio.push(source),
+					Ok(Status::Stop) => audio.clear(),
+					Ok(Status::Poke) => {},
+					Ok(Status::SetReceiver(callback)) => receiver_callback = callback,
+					Ok(Status::Disconnect) => {
+						*state.lock().unwrap() = ConnectionState::Closed;
+						return Ok(())
+					}
+					Err(mpsc::TryRecvError::Empty) => break,
+					Err(mpsc::TryRecvError::Disconnected) => {
+						*state.lock().unwrap() = ConnectionState::Closed;
+						return Ok(())
+					}
+				}
+			}
+
+			match establish(user_id, &server_id, &session_id, &token, &endpoint) {
+				Ok(session) => break session,
+				Err(err) => {
+					warn!("Voice reconnect failed, retrying in {}ms: {:?}", backoff_ms, err);
+					::sleep_ms(backoff_ms);
+					backoff_ms = (backoff_ms * 2).min(4000);
+				}
+			}
+		};
+		*state.lock().unwrap() = ConnectionState::Connected;
+		info!("Voice reconnected to {}", endpoint);
+		backoff_ms = 1000;
+	}
 }
 
 fn next_frame(source: &mut AudioSource, buffer: &mut [i16]) -> Result<usize> {
@@ -397,6 +887,71 @@ fn next_frame(source: &mut AudioSource, buffer: &mut [i16]) -> Result<usize> {
 	Ok(buffer.len())
 }
 
+/// Read one length-prefixed Opus frame, or `None` at a clean end-of-stream.
+fn next_opus_frame(source: &mut OpusFrameSource) -> Result<Option<Vec<u8>>> {
+	let len = match source.read_i16::<LittleEndian>() {
+		Ok(len) => len,
+		Err(::byteorder::Error::UnexpectedEOF) => return Ok(None),
+		Err(::byteorder::Error::Io(e)) => return Err(From::from(e)),
+	};
+	if len < 0 {
+		return Err(Error::Protocol("Opus frame length prefix was negative"))
+	}
+	let mut buffer = vec![0; len as usize];
+	try!(source.read_exact(&mut buffer));
+	Ok(Some(buffer))
+}
+
+/// Build the RTP header and nonce for a packet carrying `opus_data`, seal it
+/// with the session's encryption key, and send it to `destination`.
+fn send_voice_packet(
+	udp: &UdpSocket,
+	destination: SocketAddr,
+	packet: &mut Vec<u8>,
+	sequence: u16,
+	timestamp: u32,
+	ssrc: u32,
+	encryption_mode: EncryptionMode,
+	encryption_key: &::sodiumoxide::crypto::secretbox::Key,
+	lite_nonce: &mut u32,
+	opus_data: &[u8],
+) -> Result<()> {
+	use sodiumoxide::crypto::secretbox as crypto;
+	use std::io::Cursor;
+
+	const HEADER_LEN: usize = 12;
+	packet.clear();
+	try!(packet.write_all(&[0x80, 0x78]));
+	try!(packet.write_u16::<BigEndian>(sequence));
+	try!(packet.write_u32::<BigEndian>(timestamp));
+	try!(packet.write_u32::<BigEndian>(ssrc));
+
+	// derive the nonce for this packet according to the negotiated mode
+	let nonce = match encryption_mode {
+		EncryptionMode::Normal => {
+			let mut nonce = crypto::Nonce([0; 24]);
+			nonce.0[..HEADER_LEN].clone_from_slice(&packet[..HEADER_LEN]);
+			nonce
+		}
+		EncryptionMode::Suffix => crypto::gen_nonce(),
+		EncryptionMode::Lite => {
+			let mut nonce = crypto::Nonce([0; 24]);
+			try!(Cursor::new(&mut nonce.0[..4]).write_u32::<BigEndian>(*lite_nonce));
+			*lite_nonce = lite_nonce.wrapping_add(1);
+			nonce
+		}
+	};
+
+	packet.extend(crypto::seal(opus_data, &nonce, encryption_key));
+	match encryption_mode {
+		EncryptionMode::Normal => {}
+		EncryptionMode::Suffix => packet.extend(&nonce.0[..]),
+		EncryptionMode::Lite => packet.extend(&nonce.0[..4]),
+	}
+	try!(udp.send_to(&packet[..], destination));
+	Ok(())
+}
+
 fn set_speaking(sender: &mut Sender<WebSocketStream>, store: &mut bool, speaking: bool) -> Result<()> {
 	if *store == speaking { return Ok(()) }
 	*store = speaking;
@@ -411,7 +966,115 @@ fn set_speaking(sender: &mut Sender<WebSocketStream>, store: &mut bool, speaking
 	sender.send_message(&WsMessage::text(try!(serde_json::to_string(&map)))).map_err(From::from)
 }
 
-fn drain_thread(mut receiver: Receiver<WebSocketStream>) -> Receiver<WebSocketStream> {
-	while let Ok(_) = recv_message(&mut receiver) {}
+fn drain_thread(
+	mut receiver: Receiver<WebSocketStream>,
+	ssrc_map: Arc<Mutex<HashMap<u32, UserId>>>,
+) -> Receiver<WebSocketStream> {
+	loop {
+		match recv_message(&mut receiver) {
+			Ok(VoiceEvent::Unknown(5, value)) => {
+				// a Speaking update - track which ssrc belongs to which user
+				// so incoming voice packets can be attributed correctly
+				if let Some(map) = value.as_object() {
+					let user_id = map.get("user_id")
+						.and_then(serde_json::Value::as_string)
+						.and_then(|s| s.parse().ok())
+						.map(UserId);
+					let ssrc = map.get("ssrc").and_then(serde_json::Value::as_u64);
+					if let (Some(user_id), Some(ssrc)) = (user_id, ssrc) {
+						ssrc_map.lock().unwrap().insert(ssrc as u32, user_id);
+					}
+				}
+			}
+			Ok(_) => {}
+			Err(_) => break,
+		}
+	}
 	receiver
 }
+
+fn voice_receive_thread(
+	udp: UdpSocket,
+	encryption_key: ::sodiumoxide::crypto::secretbox::Key,
+	encryption_mode: EncryptionMode,
+	ssrc_map: Arc<Mutex<HashMap<u32, UserId>>>,
+	channel: mpsc::Sender<(UserId, Vec<i16>)>,
+	closed: Arc<AtomicBool>,
+) {
+	use opus;
+	use sodiumoxide::crypto::secretbox as crypto;
+	use std::time::Duration;
+
+	let mut decoder = match opus::Decoder::new(48000, opus::Channels::Mono) {
+		Ok(decoder) => decoder,
+		Err(e) => return warn!("Failed to start voice decoder: {:?}", e),
+	};
+
+	// wake up periodically to check `closed`, since a plain blocking
+	// `recv_from` would otherwise never notice the session being torn down
+	let _ = udp.set_read_timeout(Some(Duration::from_millis(500)));
+
+	let mut buffer = [0; 1024];
+	let mut output = [0i16; 960 * 2];
+	loop {
+		if closed.load(Ordering::Relaxed) {
+			break
+		}
+		let (len, _addr) = match udp.recv_from(&mut buffer) {
+			Ok(result) => result,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+			Err(_) => break,
+		};
+		if len < 12 || (buffer[0] != 0x80 && buffer[0] != 0x90) {
+			continue
+		}
+		let header = buffer[..12].to_vec();
+		let ssrc = BigEndian::read_u32(&header[8..12]);
+
+		// the nonce is derived differently, and trails the packet
+		// differently, depending on the negotiated encryption mode
+		let (nonce, ciphertext_end) = match encryption_mode {
+			EncryptionMode::Normal => {
+				let mut nonce = crypto::Nonce([0; 24]);
+				nonce.0[..12].clone_from_slice(&header);
+				(nonce, len)
+			}
+			EncryptionMode::Suffix => {
+				if len < 12 + 24 { continue }
+				let mut nonce = crypto::Nonce([0; 24]);
+				nonce.0.clone_from_slice(&buffer[len - 24..len]);
+				(nonce, len - 24)
+			}
+			EncryptionMode::Lite => {
+				if len < 12 + 4 { continue }
+				let mut nonce = crypto::Nonce([0; 24]);
+				nonce.0[..4].clone_from_slice(&buffer[len - 4..len]);
+				(nonce, len - 4)
+			}
+		};
+		let mut payload = match crypto::open(&buffer[12..ciphertext_end], &nonce, &encryption_key) {
+			Ok(payload) => payload,
+			Err(()) => continue,
+		};
+
+		if header[0] == 0x90 {
+			// skip the RTP extension header and its declared data words
+			if payload.len() < 4 { continue }
+			let ext_len = BigEndian::read_u16(&payload[2..4]) as usize;
+			if payload.len() < 4 + ext_len * 4 { continue }
+			payload = payload.split_off(4 + ext_len * 4);
+		}
+
+		let samples = match decoder.decode(&payload, &mut output, false) {
+			Ok(samples) => samples,
+			Err(_) => continue,
+		};
+		let user_id = match ssrc_map.lock().unwrap().get(&ssrc) {
+			Some(&user_id) => user_id,
+			None => continue,
+		};
+		if channel.send((user_id, output[..samples].to_vec())).is_err() {
+			break
+		}
+	}
+}